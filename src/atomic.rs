@@ -0,0 +1,104 @@
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
+
+use identity::PtrIdentity;
+
+fn ptr_key<T: ?Sized>(arc: &Arc<RwLock<T>>) -> usize {
+    Arc::as_ptr(arc) as *const () as usize
+}
+
+/// The `Send + Sync` counterpart of `HashableRef`, for identity-keyed graphs
+/// that need to cross threads. Mirrors its core API: `new`, `read`/
+/// `write` in place of `borrow`/`borrow_mut`, `downgrade`/`upgrade`, and
+/// identity `Hash`/`Eq`/`Clone`. `HashableRef`'s `adopt`/`unadopt` cycle
+/// collection and its traversal methods have no counterpart here.
+pub struct AtomicHashableRef<T: ?Sized>(pub Arc<RwLock<T>>);
+
+impl<T> AtomicHashableRef<T> {
+    pub fn new(obj: T) -> AtomicHashableRef<T> {
+        AtomicHashableRef(Arc::new(RwLock::new(obj)))
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        self.0.read().unwrap()
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.0.write().unwrap()
+    }
+
+    pub fn downgrade(&self) -> WeakAtomicHashableRef<T> {
+        WeakAtomicHashableRef(Arc::downgrade(&self.0), ptr_key(&self.0))
+    }
+}
+
+impl<T> Clone for AtomicHashableRef<T> {
+    fn clone(&self) -> AtomicHashableRef<T> {
+        AtomicHashableRef(self.0.clone())
+    }
+}
+
+// Captures the allocation's address at `downgrade()` time, same as
+// `WeakHashableRef`, so identity `Hash`/`Eq` stay total even once the
+// target has been dropped.
+pub struct WeakAtomicHashableRef<T: ?Sized>(pub Weak<RwLock<T>>, usize);
+
+impl<T> WeakAtomicHashableRef<T> {
+    pub fn upgrade(&self) -> Option<AtomicHashableRef<T>> {
+        self.0.upgrade().map(AtomicHashableRef)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.0.strong_count() == 0
+    }
+
+    pub fn ptr_eq(&self, other: &WeakAtomicHashableRef<T>) -> bool {
+        self.1 == other.1
+    }
+}
+
+impl<T> Clone for WeakAtomicHashableRef<T> {
+    fn clone(&self) -> WeakAtomicHashableRef<T> {
+        WeakAtomicHashableRef(self.0.clone(), self.1)
+    }
+}
+
+impl<T: ?Sized> PtrIdentity for AtomicHashableRef<T> {
+    fn ptr_identity(&self) -> usize {
+        ptr_key(&self.0)
+    }
+}
+
+impl<T: ?Sized> PtrIdentity for WeakAtomicHashableRef<T> {
+    fn ptr_identity(&self) -> usize {
+        self.1
+    }
+}
+
+impl<T> Hash for AtomicHashableRef<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr_identity().hash(state);
+    }
+}
+
+impl<T> PartialEq for AtomicHashableRef<T> {
+    fn eq(&self, other: &AtomicHashableRef<T>) -> bool {
+        self.ptr_identity() == other.ptr_identity()
+    }
+}
+
+impl<T> Eq for AtomicHashableRef<T> {}
+
+impl<T> Hash for WeakAtomicHashableRef<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr_identity().hash(state);
+    }
+}
+
+impl<T> PartialEq for WeakAtomicHashableRef<T> {
+    fn eq(&self, other: &WeakAtomicHashableRef<T>) -> bool {
+        self.ptr_identity() == other.ptr_identity()
+    }
+}
+
+impl<T> Eq for WeakAtomicHashableRef<T> {}
@@ -0,0 +1,51 @@
+use std::hash::{BuildHasher, Hasher};
+
+// Shared by the `Rc`/`RefCell`-backed types in `lib.rs` and the
+// `Arc`/`RwLock`-backed types in `atomic.rs`, so the two families hash and
+// compare by pointer identity the same way instead of drifting apart.
+pub(crate) trait PtrIdentity {
+    fn ptr_identity(&self) -> usize;
+}
+
+/// A `Hasher` that just passes a pointer-sized identity value through
+/// unchanged, instead of running it through SipHash. Meant to back
+/// `HashMap`/`HashSet`s keyed on `HashableRef`/`AtomicHashableRef` and their
+/// weak counterparts, where the "hash" is already a well-distributed
+/// allocation address and mixing it further only costs time.
+#[derive(Default)]
+pub struct IdentityHasher(u64);
+
+impl Hasher for IdentityHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        // The pointer-sized identity hashed from `ptr_identity()` arrives
+        // through `write_usize` below, not here; anything else is folded in
+        // rather than panicking, so this hasher stays usable as a
+        // general-purpose one.
+        for byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ u64::from(*byte);
+        }
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.0 = value as u64;
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.0 = value;
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct IdentityBuildHasher;
+
+impl BuildHasher for IdentityBuildHasher {
+    type Hasher = IdentityHasher;
+
+    fn build_hasher(&self) -> IdentityHasher {
+        IdentityHasher::default()
+    }
+}
@@ -1,6 +1,21 @@
+#![feature(coerce_unsized, unsize)]
+
 use std::rc::{Rc, Weak};
 use std::cell::{Ref, RefMut, RefCell};
 use std::hash::{Hash, Hasher};
+use std::collections::{HashMap, HashSet};
+use std::marker::Unsize;
+use std::ops::CoerceUnsized;
+
+mod identity;
+mod atomic;
+mod traverse;
+
+pub use identity::{IdentityBuildHasher, IdentityHasher};
+pub use atomic::{AtomicHashableRef, WeakAtomicHashableRef};
+pub use traverse::Visit;
+
+use identity::PtrIdentity;
 
 pub struct HashableRef<T: ?Sized> (pub Rc<RefCell<T>>);
 
@@ -8,7 +23,13 @@ impl<T> HashableRef<T> {
     pub fn new(obj : T) -> HashableRef<T> {
         HashableRef(Rc::new(RefCell::new((obj))))
     }
+}
 
+// `new` needs a concrete, sized `T` to construct a `RefCell<T>` from; every
+// other method only ever touches the `Rc`/`RefCell` through a reference, so
+// it works just as well for unsized `T` (trait objects in particular) as it
+// does for `Rc<RefCell<T>>` itself.
+impl<T: ?Sized> HashableRef<T> {
     pub fn borrow(&self) -> Ref<T> {
         self.0.borrow()
     }
@@ -18,68 +39,292 @@ impl<T> HashableRef<T> {
     }
 
     pub fn downgrade(&self) -> WeakHashableRef<T> {
-        WeakHashableRef(Rc::downgrade(&self.0))
+        WeakHashableRef(Rc::downgrade(&self.0), ptr_key(&self.0))
+    }
+
+    // Records that `self` owns a strong reference to `other`, via the
+    // adopt-link side table rather than a field on `T`. `T` should keep its
+    // own back-edges as `Weak`; `adopt` is what makes them "real" owning
+    // edges, and cycles made only of adopted links are reclaimed once
+    // nothing outside the cycle can reach them (see `Drop`, below).
+    pub fn adopt(&self, other: &HashableRef<T>) where T: 'static {
+        let owner = ptr_key(&self.0);
+        let target = ptr_key(&other.0);
+
+        ADOPT_IDENTITY.with(|idents| {
+            let mut idents = idents.borrow_mut();
+            idents.entry(owner).or_insert_with(|| strong_count_fn(self));
+            idents.entry(target).or_insert_with(|| strong_count_fn(other));
+        });
+
+        let held = other.clone();
+        let release: Box<dyn FnOnce()> = Box::new(move || drop(held));
+        ADOPT_LINKS.with(|links| {
+            links.borrow_mut()
+                .entry(owner)
+                .or_insert_with(HashMap::new)
+                .entry(target)
+                .or_insert_with(AdoptEdge::default)
+                .release
+                .push(release);
+        });
+    }
+
+    // Undoes one `adopt(other)` call. If `self` adopted `other` more than
+    // once, this only decrements the count; the link is removed once the
+    // last one is gone.
+    pub fn unadopt(&self, other: &HashableRef<T>) where T: 'static {
+        let owner = ptr_key(&self.0);
+        let target = ptr_key(&other.0);
+
+        let release = ADOPT_LINKS.with(|links| {
+            let mut links = links.borrow_mut();
+            let mut released = None;
+            if let Some(edges) = links.get_mut(&owner) {
+                if let Some(edge) = edges.get_mut(&target) {
+                    released = edge.release.pop();
+                    if edge.release.is_empty() {
+                        edges.remove(&target);
+                        if edges.is_empty() {
+                            links.remove(&owner);
+                        }
+                    }
+                }
+            }
+            released
+        });
+
+        // Run outside the table borrow: dropping `held` may itself drop the
+        // last external reference to `other` and recurse back in here.
+        if let Some(release) = release {
+            release();
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for HashableRef<T> {
+    fn drop(&mut self) {
+        // A node nobody else holds a strong reference to can't be keeping a
+        // cycle alive; let the ordinary `Rc` drop glue run.
+        if Rc::strong_count(&self.0) > 1 {
+            collect_cycle_if_orphaned(ptr_key(&self.0));
+        }
     }
 }
 
-impl<T> Clone for HashableRef<T> {
-    fn clone(&self) -> HashableRef<T> { 
+// One strong hold per unit of adopt multiplicity. Each `release` closure
+// owns exactly one `HashableRef<T>` clone of the target; calling it drops
+// that one strong reference.
+#[derive(Default)]
+struct AdoptEdge {
+    release: Vec<Box<dyn FnOnce()>>,
+}
+
+// The adopt-link side table and the per-node identity table are keyed by
+// pointer identity only (`usize`), so they don't need to be generic over
+// `T` and can live in a single pair of thread-locals shared by every
+// `HashableRef<T>` instantiation. `Drop` can then be implemented once for
+// `HashableRef<T: ?Sized>`, matching the type's own bound, instead of
+// needing a `T: 'static` bound that `Drop` impls aren't allowed to add.
+thread_local! {
+    static ADOPT_LINKS: RefCell<HashMap<usize, HashMap<usize, AdoptEdge>>> =
+        RefCell::new(HashMap::new());
+    static ADOPT_IDENTITY: RefCell<HashMap<usize, Box<dyn Fn() -> usize>>> =
+        RefCell::new(HashMap::new());
+}
+
+fn ptr_key<T: ?Sized>(rc: &Rc<RefCell<T>>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+// `Weak::strong_count` reads the count without ever materializing a
+// `HashableRef`; going through `upgrade()` instead would hand back a strong
+// reference whose own drop (once this closure's call returns) re-enters
+// `HashableRef::drop` for the same pointer, recursing without a base case.
+fn strong_count_fn<T: ?Sized + 'static>(node: &HashableRef<T>) -> Box<dyn Fn() -> usize> {
+    let weak = Rc::downgrade(&node.0);
+    Box::new(move || weak.strong_count())
+}
+
+// Runs whenever a `HashableRef` to `start` drops while other strong
+// references to it remain. Walks the weakly-connected component reachable
+// via adopt-links in either direction; if every strong reference to every
+// node in that component (other than the one being dropped) is accounted
+// for by an adopt-link from a node inside the component, self-loops
+// included, the whole component is an unreachable cycle and is torn down
+// together.
+fn collect_cycle_if_orphaned(start: usize) {
+    let component = ADOPT_LINKS.with(|links| {
+        ADOPT_IDENTITY.with(|idents| {
+            let links = links.borrow();
+            let idents = idents.borrow();
+
+            let mut component = vec![start];
+            let mut seen = HashSet::new();
+            seen.insert(start);
+            let mut i = 0;
+            while i < component.len() {
+                let ptr = component[i];
+                i += 1;
+                if let Some(targets) = links.get(&ptr) {
+                    for &target in targets.keys() {
+                        if seen.insert(target) {
+                            component.push(target);
+                        }
+                    }
+                }
+                for (&owner, targets) in links.iter() {
+                    if targets.contains_key(&ptr) && seen.insert(owner) {
+                        component.push(owner);
+                    }
+                }
+            }
+
+            let incoming = |ptr: usize| -> usize {
+                links.values()
+                    .filter_map(|targets| targets.get(&ptr))
+                    .map(|edge| edge.release.len())
+                    .sum()
+            };
+
+            for &ptr in &component {
+                let strong = match idents.get(&ptr) {
+                    Some(count_of) => count_of(),
+                    // Not a node any adopt-link ever touched: can't be part
+                    // of a cycle.
+                    None => return None,
+                };
+                let external = if ptr == start { strong.saturating_sub(1) } else { strong };
+                if external != incoming(ptr) {
+                    return None;
+                }
+            }
+
+            Some(component)
+        })
+    });
+
+    let component = match component {
+        Some(component) => component,
+        None => return,
+    };
+
+    // Null out the links before dropping anything: the `release` closures
+    // below drop `HashableRef`s in turn, re-entering this module's `Drop`
+    // impl, and they must find an already-torn-down table rather than the
+    // same links again.
+    let mut drained = Vec::new();
+    ADOPT_LINKS.with(|links| {
+        let mut links = links.borrow_mut();
+        for &ptr in &component {
+            if let Some(edges) = links.remove(&ptr) {
+                for (_, edge) in edges {
+                    drained.extend(edge.release);
+                }
+            }
+        }
+    });
+    ADOPT_IDENTITY.with(|idents| {
+        let mut idents = idents.borrow_mut();
+        for &ptr in &component {
+            idents.remove(&ptr);
+        }
+    });
+
+    for release in drained {
+        release();
+    }
+}
+
+impl<T: ?Sized> Clone for HashableRef<T> {
+    fn clone(&self) -> HashableRef<T> {
         HashableRef(self.0.clone())
     }
 }
 
-pub struct WeakHashableRef<T: ?Sized> (pub Weak<RefCell<T>>);
+// Lets a `HashableRef<Concrete>` coerce to a `HashableRef<dyn Trait>`,
+// matching how `Rc<RefCell<T>>` itself coerces.
+impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<HashableRef<U>> for HashableRef<T> {}
 
-impl<T> WeakHashableRef<T> {
+// The second field is the allocation's address as captured at `downgrade()`
+// time, so identity `Hash`/`Eq` stay total and infallible even once the
+// target has been dropped and `self.0.upgrade()` would return `None`.
+pub struct WeakHashableRef<T: ?Sized> (pub Weak<RefCell<T>>, usize);
+
+impl<T: ?Sized> WeakHashableRef<T> {
     pub fn upgrade(&self) -> Option<HashableRef<T>> {
         if let Some(x) = self.0.upgrade() {
             return Some(HashableRef(x))
         };
         None
     }
+
+    pub fn is_expired(&self) -> bool {
+        self.0.strong_count() == 0
+    }
+
+    pub fn ptr_eq(&self, other: &WeakHashableRef<T>) -> bool {
+        self.1 == other.1
+    }
+}
+
+impl<T: ?Sized> Clone for WeakHashableRef<T> {
+    fn clone(&self) -> WeakHashableRef<T> {
+        WeakHashableRef(self.0.clone(), self.1)
+    }
+}
+
+impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<WeakHashableRef<U>> for WeakHashableRef<T> {}
+
+impl<T: ?Sized> PtrIdentity for HashableRef<T> {
+    fn ptr_identity(&self) -> usize {
+        ptr_key(&self.0)
+    }
 }
 
-impl<T> Clone for WeakHashableRef<T> {
-    fn clone(&self) -> WeakHashableRef<T> { 
-        WeakHashableRef(self.0.clone())
+impl<T: ?Sized> PtrIdentity for WeakHashableRef<T> {
+    fn ptr_identity(&self) -> usize {
+        self.1
     }
 }
 
-impl<T> Hash for HashableRef<T> {
+impl<T: ?Sized> Hash for HashableRef<T> {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.as_ptr().hash(state);
+        self.ptr_identity().hash(state);
     }
 }
 
-impl<T> PartialEq for HashableRef<T> {
+impl<T: ?Sized> PartialEq for HashableRef<T> {
     fn eq(&self, other: &HashableRef<T>) -> bool {
-        self.0.as_ptr() == other.0.as_ptr()
+        self.ptr_identity() == other.ptr_identity()
     }
 }
 
-impl<T> Eq for HashableRef<T> {
+impl<T: ?Sized> Eq for HashableRef<T> {
 }
 
-impl<T> Hash for WeakHashableRef<T> {
+impl<T: ?Sized> Hash for WeakHashableRef<T> {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.upgrade().unwrap().as_ptr().hash(state);
+        self.ptr_identity().hash(state);
     }
 }
 
-impl<T> PartialEq for WeakHashableRef<T> {
+impl<T: ?Sized> PartialEq for WeakHashableRef<T> {
     fn eq(&self, other: &WeakHashableRef<T>) -> bool {
-        self.0.upgrade().unwrap().as_ptr() == other.0.upgrade().unwrap().as_ptr()
+        self.ptr_identity() == other.ptr_identity()
     }
 }
 
-impl<T> Eq for WeakHashableRef<T> {}
+impl<T: ?Sized> Eq for WeakHashableRef<T> {}
 
 #[cfg(test)]
 mod tests {
     use std::rc::{Rc};
+    use std::cell::Cell;
     use std::ops::Deref;
-    use std::collections::HashMap;
-    use {HashableRef, WeakHashableRef};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+    use {AtomicHashableRef, HashableRef, IdentityBuildHasher, WeakHashableRef};
 
     #[test]
     fn can_clone_refs() {
@@ -227,4 +472,209 @@ mod tests {
         assert_eq!(count_nodes(&a.borrow()), 5);
         assert_eq!(count_nodes_stack(&a.borrow()), 5);
     }
+
+    fn node_children(node: &HashableRef<Node>) -> Vec<HashableRef<Node>> {
+        node.borrow().0.clone()
+    }
+
+    #[test]
+    fn reachable_set_counts_a_dag_without_revisiting_shared_nodes() {
+        let a = HashableRef::new(Node(Vec::new()));
+        let b = HashableRef::new(Node(Vec::new()));
+        let c = HashableRef::new(Node(Vec::new()));
+        let d = HashableRef::new(Node(Vec::new()));
+
+        // a -> b -> d, a -> c -> d: d is reachable two ways but only counted once.
+        b.borrow_mut().0.push(d.clone());
+        c.borrow_mut().0.push(d.clone());
+        a.borrow_mut().0.push(b);
+        a.borrow_mut().0.push(c);
+
+        assert_eq!(a.reachable_set(node_children).len(), 4);
+        assert!(!a.contains_cycle(node_children));
+        assert_eq!(a.traverse_bfs(node_children).count(), 4);
+        assert_eq!(a.traverse_dfs(node_children).count(), 4);
+    }
+
+    #[test]
+    fn traversal_terminates_and_flags_a_genuine_cycle() {
+        let a = HashableRef::new(Node(Vec::new()));
+        let b = HashableRef::new(Node(Vec::new()));
+        a.borrow_mut().0.push(b.clone());
+        b.borrow_mut().0.push(a.clone());
+
+        // count_nodes_stack would spin forever on this graph; traverse_bfs
+        // dedupes by identity and terminates.
+        assert_eq!(a.reachable_set(node_children).len(), 2);
+        assert!(a.contains_cycle(node_children));
+    }
+
+    #[test]
+    fn weak_keys_stay_usable_after_the_target_is_dropped() {
+        let mut h = HashMap::<WeakHashableRef<String>, i32>::new();
+
+        let a = HashableRef::new(String::from("A"));
+        let b = HashableRef::new(String::from("B"));
+
+        h.insert(a.downgrade(), 1);
+        h.insert(b.downgrade(), 2);
+
+        let dangling = a.downgrade();
+        drop(a);
+
+        assert!(dangling.is_expired());
+        assert!(dangling.upgrade().is_none());
+
+        // Hashing and comparing a dangling weak key no longer panics, so
+        // dead entries can be swept out of the map.
+        assert_eq!(h.get(&dangling), Some(&1));
+        h.retain(|k, _| !k.is_expired());
+
+        assert_eq!(h.len(), 1);
+        assert_eq!(h.get(&b.downgrade()), Some(&2));
+        assert!(b.downgrade().ptr_eq(&b.downgrade()));
+    }
+
+    #[test]
+    fn atomic_ref_is_identity_keyed_and_shareable_across_threads() {
+        let a = AtomicHashableRef::new(0i32);
+        let b = a.clone();
+
+        {
+            let mut guard = a.write();
+            *guard += 1;
+        }
+        assert_eq!(*b.read(), 1);
+
+        let mut h = HashMap::<AtomicHashableRef<i32>, &str>::new();
+        h.insert(a.clone(), "a");
+        assert_eq!(h.get(&b), Some(&"a"));
+
+        let handle = std::thread::spawn(move || {
+            *b.write() += 1;
+            b.downgrade()
+        });
+        let weak = handle.join().unwrap();
+        assert_eq!(*a.read(), 2);
+        assert!(weak.upgrade().is_some());
+
+        assert_eq!(Arc::strong_count(&a.0), 2);
+    }
+
+    trait Shout {
+        fn shout(&self) -> String;
+    }
+
+    struct Loud;
+    impl Shout for Loud {
+        fn shout(&self) -> String {
+            String::from("LOUD")
+        }
+    }
+
+    struct Quiet;
+    impl Shout for Quiet {
+        fn shout(&self) -> String {
+            String::from("quiet")
+        }
+    }
+
+    #[test]
+    fn hashable_ref_coerces_to_a_trait_object() {
+        let loud: HashableRef<dyn Shout> = HashableRef::new(Loud);
+        let quiet: HashableRef<dyn Shout> = HashableRef::new(Quiet);
+
+        assert_eq!(loud.borrow().shout(), "LOUD");
+        assert_eq!(quiet.borrow().shout(), "quiet");
+
+        let mut heterogeneous = HashSet::new();
+        heterogeneous.insert(loud.clone());
+        heterogeneous.insert(quiet.clone());
+
+        assert_eq!(heterogeneous.len(), 2);
+        assert!(heterogeneous.contains(&loud));
+
+        let another_loud: HashableRef<dyn Shout> = HashableRef::new(Loud);
+        assert!(!heterogeneous.contains(&another_loud));
+    }
+
+    struct Tracked(Rc<Cell<bool>>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    #[test]
+    fn adopted_self_loop_is_reclaimed_when_its_only_external_owner_drops() {
+        let dropped = Rc::new(Cell::new(false));
+        let a = HashableRef::new(Tracked(dropped.clone()));
+
+        a.adopt(&a);
+        assert_eq!(Rc::strong_count(&a.0), 2);
+
+        drop(a);
+        assert!(dropped.get());
+    }
+
+    #[test]
+    fn adopted_cycle_is_reclaimed_once_both_external_owners_drop() {
+        let dropped_a = Rc::new(Cell::new(false));
+        let dropped_b = Rc::new(Cell::new(false));
+        let a = HashableRef::new(Tracked(dropped_a.clone()));
+        let b = HashableRef::new(Tracked(dropped_b.clone()));
+
+        a.adopt(&b);
+        b.adopt(&a);
+
+        drop(b);
+        // `a` is still alive outside the cycle, so neither node is reclaimed yet.
+        assert!(!dropped_a.get());
+        assert!(!dropped_b.get());
+
+        drop(a);
+        assert!(dropped_a.get());
+        assert!(dropped_b.get());
+    }
+
+    #[test]
+    fn unadopt_decrements_a_multiply_adopted_link_instead_of_removing_it() {
+        let dropped = Rc::new(Cell::new(false));
+        let a = HashableRef::new(Tracked(Rc::new(Cell::new(false))));
+        let b = HashableRef::new(Tracked(dropped.clone()));
+
+        a.adopt(&b);
+        a.adopt(&b);
+        assert_eq!(Rc::strong_count(&b.0), 3);
+
+        a.unadopt(&b);
+        assert_eq!(Rc::strong_count(&b.0), 2);
+
+        let weak = b.downgrade();
+        drop(b);
+        // One adopt link still holds `b` alive.
+        assert!(weak.upgrade().is_some());
+        assert!(!dropped.get());
+
+        a.unadopt(&weak.upgrade().unwrap());
+        assert!(dropped.get());
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn identity_build_hasher_buckets_by_pointer_identity() {
+        let a = HashableRef::new(String::from("A"));
+        let b = HashableRef::new(String::from("B"));
+
+        let mut h: HashMap<HashableRef<String>, i32, IdentityBuildHasher> =
+            HashMap::with_hasher(IdentityBuildHasher::default());
+
+        h.insert(a.clone(), 1);
+        h.insert(b.clone(), 2);
+
+        assert_eq!(h.get(&a.clone()), Some(&1));
+        assert_eq!(h.get(&b.clone()), Some(&2));
+        assert_eq!(h.len(), 2);
+    }
 }
\ No newline at end of file
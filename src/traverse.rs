@@ -0,0 +1,132 @@
+use std::collections::{HashSet, VecDeque};
+
+use HashableRef;
+
+/// An iterator over a `HashableRef` graph, built by `HashableRef::traverse_dfs`/
+/// `traverse_bfs`. Dedupes by the identity `Hash`/`Eq` the crate already gives
+/// `HashableRef`, so cyclic graphs terminate instead of looping forever.
+pub struct Visit<T, C> {
+    queue: VecDeque<HashableRef<T>>,
+    visited: HashSet<HashableRef<T>>,
+    children: C,
+    depth_first: bool,
+}
+
+impl<T, C, I> Visit<T, C>
+where
+    C: FnMut(&HashableRef<T>) -> I,
+    I: IntoIterator<Item = HashableRef<T>>,
+{
+    fn new(root: HashableRef<T>, children: C, depth_first: bool) -> Visit<T, C> {
+        let mut queue = VecDeque::new();
+        queue.push_back(root);
+        Visit {
+            queue,
+            visited: HashSet::new(),
+            children,
+            depth_first,
+        }
+    }
+}
+
+impl<T, C, I> Iterator for Visit<T, C>
+where
+    C: FnMut(&HashableRef<T>) -> I,
+    I: IntoIterator<Item = HashableRef<T>>,
+{
+    type Item = HashableRef<T>;
+
+    fn next(&mut self) -> Option<HashableRef<T>> {
+        loop {
+            let node = if self.depth_first {
+                self.queue.pop_back()?
+            } else {
+                self.queue.pop_front()?
+            };
+
+            if !self.visited.insert(node.clone()) {
+                continue;
+            }
+
+            for child in (self.children)(&node) {
+                self.queue.push_back(child);
+            }
+
+            return Some(node);
+        }
+    }
+}
+
+impl<T> HashableRef<T> {
+    /// Depth-first traversal starting from `self`. `children` maps a node to
+    /// the `HashableRef`s it points at; each node is yielded exactly once,
+    /// however many ways it's reachable.
+    pub fn traverse_dfs<C, I>(&self, children: C) -> Visit<T, C>
+    where
+        C: FnMut(&HashableRef<T>) -> I,
+        I: IntoIterator<Item = HashableRef<T>>,
+    {
+        Visit::new(self.clone(), children, true)
+    }
+
+    /// Breadth-first traversal starting from `self`.
+    pub fn traverse_bfs<C, I>(&self, children: C) -> Visit<T, C>
+    where
+        C: FnMut(&HashableRef<T>) -> I,
+        I: IntoIterator<Item = HashableRef<T>>,
+    {
+        Visit::new(self.clone(), children, false)
+    }
+
+    /// All nodes reachable from `self`, `self` included.
+    pub fn reachable_set<C, I>(&self, children: C) -> HashSet<HashableRef<T>>
+    where
+        C: FnMut(&HashableRef<T>) -> I,
+        I: IntoIterator<Item = HashableRef<T>>,
+    {
+        self.traverse_bfs(children).collect()
+    }
+
+    /// Whether a node reachable from `self` can reach itself again, i.e.
+    /// whether the graph rooted at `self` contains a cycle. Unlike
+    /// `reachable_set`, this tracks the current path rather than just which
+    /// nodes have been seen, so a shared (but acyclic) node reached two
+    /// different ways isn't mistaken for a cycle.
+    pub fn contains_cycle<C, I>(&self, mut children: C) -> bool
+    where
+        C: FnMut(&HashableRef<T>) -> I,
+        I: IntoIterator<Item = HashableRef<T>>,
+    {
+        enum Frame<T> {
+            Enter(HashableRef<T>),
+            Exit(HashableRef<T>),
+        }
+
+        let mut stack = vec![Frame::Enter(self.clone())];
+        let mut on_path = HashSet::new();
+        let mut done = HashSet::new();
+
+        while let Some(frame) = stack.pop() {
+            match frame {
+                Frame::Enter(node) => {
+                    if on_path.contains(&node) {
+                        return true;
+                    }
+                    if !done.insert(node.clone()) {
+                        continue;
+                    }
+                    on_path.insert(node.clone());
+                    stack.push(Frame::Exit(node.clone()));
+                    for child in children(&node) {
+                        stack.push(Frame::Enter(child));
+                    }
+                }
+                Frame::Exit(node) => {
+                    on_path.remove(&node);
+                }
+            }
+        }
+
+        false
+    }
+}